@@ -1,27 +1,35 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use futures::{stream, StreamExt};
+use futures::{stream, stream::FuturesUnordered, StreamExt};
 use rand::seq::SliceRandom;
 use reqwest::Client;
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Write};
+use std::net::IpAddr;
 use std::path::{PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use futures::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 use tokio::time::timeout;
+use tokio_socks::tcp::Socks5Stream;
+use tokio_util::compat::TokioAsyncReadCompatExt;
 
 const DESCRIPTION: &str = "Downloads all Tor Relay IP addresses from onionoo.torproject.org and checks whether random Relays are available.";
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Default)]
 #[command(author, version, about = DESCRIPTION, long_about = None)]
 struct Args {
-    #[arg(short = 'n', long, default_value_t = 30)]
-    num_relays: usize,
-    #[arg(short = 'g', long, default_value_t = 10)]
-    working_relay_num_goal: usize,
-    #[arg(long, default_value_t = 10.0)]
-    timeout: f64,
+    #[arg(short = 'n', long)]
+    num_relays: Option<usize>,
+    #[arg(short = 'g', long)]
+    working_relay_num_goal: Option<usize>,
+    #[arg(long)]
+    timeout: Option<f64>,
     #[arg(short = 'o', long = "outfile")]
     outfile: Option<PathBuf>,
     #[arg(long)]
@@ -32,12 +40,116 @@ struct Args {
     url: Vec<String>,
     #[arg(short = 'p', long)]
     port: Vec<u16>,
+    /// Confirm reachable relays actually speak Tor and match their identity.
+    #[arg(long)]
+    verify_tor: bool,
+    /// Order output by measured connect latency, fastest first.
+    #[arg(long)]
+    sort_by_latency: bool,
+    /// Only emit the N lowest-latency relays. Implies --sort-by-latency.
+    #[arg(long)]
+    top: Option<usize>,
+    /// Route each per-relay reachability check through a SOCKS5 proxy.
+    #[arg(long)]
+    check_via_socks: Option<String>,
+    /// Keep at most this many selected relays per autonomous system.
+    #[arg(long)]
+    max_per_asn: Option<usize>,
+    /// Print each selected relay's autonomous system alongside its result.
+    #[arg(long)]
+    asn_report: bool,
+    /// Load defaults from a TOML config file. CLI flags still win.
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+/// Mirrors every `Args` field, plus `mirror_urls` which has no CLI flag.
+#[derive(Deserialize, Debug, Default)]
+struct ConfigFile {
+    num_relays: Option<usize>,
+    working_relay_num_goal: Option<usize>,
+    timeout: Option<f64>,
+    outfile: Option<PathBuf>,
+    torrc_fmt: Option<bool>,
+    proxy: Option<String>,
+    url: Option<Vec<String>>,
+    port: Option<Vec<u16>>,
+    verify_tor: Option<bool>,
+    sort_by_latency: Option<bool>,
+    top: Option<usize>,
+    check_via_socks: Option<String>,
+    max_per_asn: Option<usize>,
+    asn_report: Option<bool>,
+    /// Hard-coded fallback mirrors `grab_relays` tries after the primary
+    /// onionoo endpoint and any `--url` entries.
+    mirror_urls: Option<Vec<String>>,
+}
+
+const DEFAULT_NUM_RELAYS: usize = 30;
+const DEFAULT_WORKING_RELAY_NUM_GOAL: usize = 10;
+const DEFAULT_TIMEOUT: f64 = 10.0;
+
+/// Fully resolved settings for one run, after applying CLI-over-config-over-default precedence.
+#[derive(Debug, PartialEq)]
+struct Settings {
+    num_relays: usize,
+    working_relay_num_goal: usize,
+    timeout_secs: f64,
+    outfile: Option<PathBuf>,
+    torrc_fmt: bool,
+    proxy: Option<String>,
+    urls: Vec<String>,
+    ports: Vec<u16>,
+    verify_tor: bool,
+    sort_by_latency: bool,
+    top: Option<usize>,
+    check_via_socks: Option<String>,
+    max_per_asn: Option<usize>,
+    asn_report: bool,
+    mirror_urls: Option<Vec<String>>,
+}
+
+/// Merges CLI args over config-file values over hard-coded defaults.
+fn merge_settings(args: &Args, config: &ConfigFile) -> Settings {
+    Settings {
+        num_relays: args
+            .num_relays
+            .or(config.num_relays)
+            .unwrap_or(DEFAULT_NUM_RELAYS),
+        working_relay_num_goal: args
+            .working_relay_num_goal
+            .or(config.working_relay_num_goal)
+            .unwrap_or(DEFAULT_WORKING_RELAY_NUM_GOAL),
+        timeout_secs: args.timeout.or(config.timeout).unwrap_or(DEFAULT_TIMEOUT),
+        outfile: args.outfile.clone().or(config.outfile.clone()),
+        torrc_fmt: args.torrc_fmt || config.torrc_fmt.unwrap_or(false),
+        proxy: args.proxy.clone().or(config.proxy.clone()),
+        urls: if !args.url.is_empty() {
+            args.url.clone()
+        } else {
+            config.url.clone().unwrap_or_default()
+        },
+        ports: if !args.port.is_empty() {
+            args.port.clone()
+        } else {
+            config.port.clone().unwrap_or_default()
+        },
+        verify_tor: args.verify_tor || config.verify_tor.unwrap_or(false),
+        sort_by_latency: args.sort_by_latency || config.sort_by_latency.unwrap_or(false),
+        top: args.top.or(config.top),
+        check_via_socks: args.check_via_socks.clone().or(config.check_via_socks.clone()),
+        max_per_asn: args.max_per_asn.or(config.max_per_asn),
+        asn_report: args.asn_report || config.asn_report.unwrap_or(false),
+        mirror_urls: config.mirror_urls.clone(),
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct Relay {
     fingerprint: String,
     or_addresses: Vec<String>,
+    #[serde(default)]
+    master_key_ed25519: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -68,8 +180,26 @@ fn parse_or_addresses(or_addresses: &[String]) -> Vec<(String, u16)> {
         .collect()
 }
 
-async fn check_connection(host: &str, port: u16, timeout_duration: Duration) -> io::Result<()> {
+/// A direct or SOCKS5-tunnelled stream to a relay's OR port.
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+async fn check_connection(
+    host: &str,
+    port: u16,
+    timeout_duration: Duration,
+    socks_proxy: Option<&str>,
+) -> io::Result<()> {
     let address = format_address(host, port);
+
+    if let Some(proxy) = socks_proxy {
+        return match timeout(timeout_duration, Socks5Stream::connect(proxy, address)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(io::Error::other(e)),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Connection timed out")),
+        };
+    }
+
     let addrs = tokio::net::lookup_host(address).await?;
     let mut last_err = None;
 
@@ -90,14 +220,20 @@ async fn check_connection(host: &str, port: u16, timeout_duration: Duration) ->
 
 async fn grab_relays(
     preferred_urls: &[String],
+    mirror_urls: Option<&[String]>,
     proxy: Option<&String>,
     timeout_duration: Duration,
 ) -> Result<Vec<Relay>> {
-    let base_url = "https://onionoo.torproject.org/details?type=relay&running=true&fields=fingerprint,or_addresses,country";
+    let base_url = "https://onionoo.torproject.org/details?type=relay&running=true&fields=fingerprint,or_addresses,country,master_key_ed25519";
     let mut urls = preferred_urls.to_vec();
     urls.insert(0, base_url.to_string());
-    urls.push("https://raw.githubusercontent.com/nukeddd/tor-onionoo-mirror/refs/heads/master/details-running-relays-fingerprint-address-only.json".to_string());
-    urls.push("https://bitbucket.org/ValdikSS/tor-onionoo-mirror/raw/master/details-running-relays-fingerprint-address-only.json".to_string());
+    match mirror_urls {
+        Some(mirrors) => urls.extend_from_slice(mirrors),
+        None => {
+            urls.push("https://raw.githubusercontent.com/nukeddd/tor-onionoo-mirror/refs/heads/master/details-running-relays-fingerprint-address-only.json".to_string());
+            urls.push("https://bitbucket.org/ValdikSS/tor-onionoo-mirror/raw/master/details-running-relays-fingerprint-address-only.json".to_string());
+        }
+    }
 
     let mut client_builder = Client::builder().timeout(timeout_duration);
     if let Some(p) = proxy {
@@ -128,21 +264,223 @@ async fn grab_relays(
     ))
 }
 
-async fn check_relay(relay: Relay, timeout_duration: Duration) -> (Relay, Vec<(String, u16)>) {
+/// Performs the real Tor link-protocol handshake against an already-reachable
+/// OR port: TLS first, then VERSIONS/CERTS/AUTH_CHALLENGE/NETINFO over it.
+async fn verify_tor_handshake(
+    host: &str,
+    port: u16,
+    rsa_fingerprint: &str,
+    ed25519_identity: Option<&str>,
+    timeout_duration: Duration,
+    socks_proxy: Option<&str>,
+) -> Result<()> {
+    use std::time::SystemTime;
+    use tor_linkspec::{ChannelMethod, OwnedChanTarget};
+    use tor_llcrypto::pk::{ed25519::Ed25519Identity, rsa::RsaIdentity};
+    use tor_rtcompat::tls::TlsConnector;
+    use tor_rtcompat::{CertifiedConn, NetStreamProvider, PreferredRuntime, TlsProvider};
+
+    let runtime = PreferredRuntime::current()?;
+    let address = format_address(host, port);
+    let peer_addr = tokio::net::lookup_host(&address)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no address resolved for {}", address))?;
+
+    let mut expected = OwnedChanTarget::builder();
+    expected.addrs(vec![peer_addr]);
+    expected.rsa_identity(
+        RsaIdentity::from_hex(rsa_fingerprint)
+            .ok_or_else(|| anyhow::anyhow!("bad RSA fingerprint {}", rsa_fingerprint))?,
+    );
+    if let Some(ed) = ed25519_identity {
+        use base64::Engine;
+        let bytes = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(ed.trim_end_matches('='))
+            .map_err(|_| anyhow::anyhow!("bad Ed25519 identity {}", ed))?;
+        expected.ed_identity(
+            Ed25519Identity::from_bytes(&bytes)
+                .ok_or_else(|| anyhow::anyhow!("bad Ed25519 identity {}", ed))?,
+        );
+    }
+    let expected = expected.build()?;
+
+    let tcp: Box<dyn AsyncStream> = match socks_proxy {
+        Some(proxy) => Box::new(
+            timeout(timeout_duration, Socks5Stream::connect(proxy, address))
+                .await??
+                .compat(),
+        ),
+        None => Box::new(timeout(timeout_duration, runtime.connect(&peer_addr)).await??),
+    };
+    // OR-port TLS certs are self-signed and validated later via the CERTS
+    // cell, not via webpki/SNI, so this is an unvalidated connect.
+    let tls = timeout(
+        timeout_duration,
+        runtime.tls_connector().negotiate_unvalidated(tcp, host),
+    )
+    .await??;
+    let peer_cert = tls
+        .peer_certificate()?
+        .ok_or_else(|| anyhow::anyhow!("relay presented no TLS certificate"))?;
+
+    let mut builder = tor_proto::channel::ChannelBuilder::new();
+    builder.set_declared_method(ChannelMethod::Direct(vec![peer_addr]));
+    let handshake = builder.launch(tls, runtime.clone());
+    let unverified = timeout(timeout_duration, handshake.connect(SystemTime::now)).await??;
+    let verified = unverified.check(&expected, &peer_cert, Some(SystemTime::now()))?;
+    let (channel, _reactor) = timeout(timeout_duration, verified.finish()).await??;
+    drop(channel);
+    Ok(())
+}
+
+/// Per-address ASN lookup cache, so addresses in the same /24 (or repeated
+/// across attempts) only cost one DNS query.
+type AsnCache = Mutex<HashMap<IpAddr, Option<u32>>>;
+
+/// Builds the Team Cymru DNS-based IP-to-ASN query name for `ip`.
+fn cymru_query_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.origin.asn.cymru.com.", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: String = v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|b| [b & 0x0f, b >> 4])
+                .map(|nibble| format!("{:x}.", nibble))
+                .collect();
+            format!("{}origin6.asn.cymru.com.", nibbles)
+        }
+    }
+}
+
+/// Parses Team Cymru TXT records (`"ASN | BGP-prefix | CC | registry | date"`)
+/// into the agreed-upon ASN, or `None` if the records disagree or none parse.
+fn parse_asn_txt<'a>(records: impl Iterator<Item = &'a str>) -> Option<u32> {
+    let mut found = None;
+    let mut disagreement = false;
+    for record in records {
+        let Some(field) = record.split('|').next().map(str::trim) else {
+            continue;
+        };
+        if let Ok(asn) = field.parse::<u32>() {
+            match found {
+                None => found = Some(asn),
+                Some(prev) if prev != asn => disagreement = true,
+                _ => {}
+            }
+        }
+    }
+    if disagreement {
+        None
+    } else {
+        found
+    }
+}
+
+/// Resolves an IP address to its origin AS number, caching unknowns (NXDOMAIN,
+/// lookup failures, disagreeing records) as `None` so callers never count them
+/// against a per-ASN bucket.
+async fn lookup_asn(ip: IpAddr, resolver: &TokioAsyncResolver, cache: &AsnCache) -> Option<u32> {
+    if let Some(cached) = cache.lock().await.get(&ip) {
+        return *cached;
+    }
+
+    let asn = match resolver.txt_lookup(cymru_query_name(ip)).await {
+        Ok(txts) => {
+            let texts: Vec<String> = txts.iter().map(|txt| txt.to_string()).collect();
+            parse_asn_txt(texts.iter().map(String::as_str))
+        }
+        Err(_) => None,
+    };
+
+    cache.lock().await.insert(ip, asn);
+    asn
+}
+
+/// Delay before launching each next address's attempt (RFC 8305 happy-eyeballs).
+const HAPPY_EYEBALLS_STAGGER: Duration = Duration::from_millis(250);
+
+/// Races connection attempts against all of a relay's OR addresses, returning
+/// as soon as one succeeds.
+async fn check_relay(
+    relay: Relay,
+    timeout_duration: Duration,
+    verify_tor: bool,
+    socks_proxy: Option<String>,
+) -> (Relay, Vec<(String, u16, Duration)>) {
     let addresses = parse_or_addresses(&relay.or_addresses);
     let mut reachable_addrs = Vec::new();
 
-    for (host, port) in &addresses {
-        if check_connection(host, *port, timeout_duration)
-            .await
-            .is_ok()
-        {
-            reachable_addrs.push((host.clone(), *port));
+    let mut attempts = FuturesUnordered::new();
+    for (i, (host, port)) in addresses.into_iter().enumerate() {
+        let socks_proxy = socks_proxy.clone();
+        let fingerprint = relay.fingerprint.clone();
+        let ed25519_identity = relay.master_key_ed25519.clone();
+        attempts.push(async move {
+            if i > 0 {
+                tokio::time::sleep(HAPPY_EYEBALLS_STAGGER * i as u32).await;
+            }
+            let start = Instant::now();
+            check_connection(&host, port, timeout_duration, socks_proxy.as_deref())
+                .await
+                .map_err(|_| None)?;
+            if verify_tor {
+                verify_tor_handshake(
+                    &host,
+                    port,
+                    &fingerprint,
+                    ed25519_identity.as_deref(),
+                    timeout_duration,
+                    socks_proxy.as_deref(),
+                )
+                .await
+                .map_err(|e| {
+                    Some(format!(
+                        "{} answered TCP but failed the Tor handshake: {}",
+                        format_address(&host, port),
+                        e
+                    ))
+                })?;
+            }
+            Ok::<_, Option<String>>((host, port, start.elapsed()))
+        });
+    }
+
+    while let Some(attempt) = attempts.next().await {
+        match attempt {
+            Ok(winner) => {
+                reachable_addrs.push(winner);
+                break;
+            }
+            Err(Some(e)) => eprintln!("-> {}", e),
+            Err(None) => {}
         }
     }
     (relay, reachable_addrs)
 }
 
+/// Formats one result line, omitting RTT in `--torrc-fmt` mode.
+fn format_result_line(
+    bridge_prefix: &str,
+    torrc_fmt: bool,
+    host: &str,
+    port: u16,
+    fingerprint: &str,
+    rtt: Duration,
+) -> String {
+    let addr = format_address(host, port);
+    if torrc_fmt {
+        format!("{}{} {}\n", bridge_prefix, addr, fingerprint)
+    } else {
+        format!("{}{} {} ({}ms)\n", bridge_prefix, addr, fingerprint, rtt.as_millis())
+    }
+}
+
 
 /// Filters relays by port, returning a new list of relays with modified `or_addresses`.
 fn filter_by_port(relays: &[Relay], ports: &[u16]) -> Vec<Relay> {
@@ -171,25 +509,53 @@ fn filter_by_port(relays: &[Relay], ports: &[u16]) -> Vec<Relay> {
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    if let Some(path) = &args.outfile {
+    let config: ConfigFile = match &args.config {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("couldn't read config file {}", path.display()))?;
+            toml::from_str(&text)
+                .with_context(|| format!("couldn't parse config file {}", path.display()))?
+        }
+        None => ConfigFile::default(),
+    };
+
+    let Settings {
+        num_relays,
+        working_relay_num_goal,
+        timeout_secs,
+        outfile,
+        torrc_fmt,
+        proxy,
+        urls,
+        ports,
+        verify_tor,
+        sort_by_latency,
+        top,
+        check_via_socks,
+        max_per_asn,
+        asn_report,
+        mirror_urls,
+    } = merge_settings(&args, &config);
+
+    if let Some(path) = &outfile {
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
         fs::File::create(path)?;
     }
-    let timeout_duration = Duration::from_secs_f64(args.timeout);
-    let bridge_prefix = if args.torrc_fmt { "Bridge " } else { "" };
+    let timeout_duration = Duration::from_secs_f64(timeout_secs);
+    let bridge_prefix = if torrc_fmt { "Bridge " } else { "" };
 
     println!(
         "Tor Relay Scanner. Will scan for up to {} working relays.",
-        args.working_relay_num_goal
+        working_relay_num_goal
     );
     println!("Downloading Tor Relay information...");
 
-    let mut relays = grab_relays(&args.url, args.proxy.as_ref(), timeout_duration).await?;
+    let mut relays = grab_relays(&urls, mirror_urls.as_deref(), proxy.as_ref(), timeout_duration).await?;
     println!("Done! Found {} relays.", relays.len());
 
-    relays = filter_by_port(&relays, &args.port);
+    relays = filter_by_port(&relays, &ports);
 
     if relays.is_empty() {
         println!("No relays selected after filtering. Check your country/port constraints.");
@@ -198,12 +564,22 @@ async fn main() -> Result<()> {
 
     relays.shuffle(&mut rand::thread_rng());
 
+    // --top implies sorting so "the N lowest-latency relays" is meaningful;
+    // both defer printing until every attempt is in instead of streaming
+    // results out as they're found.
+    let buffered = sort_by_latency || top.is_some();
+
+    let want_asn = max_per_asn.is_some() || asn_report;
+    let asn_resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
+    let asn_cache: AsnCache = Mutex::new(HashMap::new());
+    let mut asn_counts: HashMap<u32, usize> = HashMap::new();
+
     let mut working_relays = Vec::new();
-    let chunks: Vec<_> = relays.chunks(args.num_relays).collect();
+    let chunks: Vec<_> = relays.chunks(num_relays).collect();
     let num_tries = chunks.len();
 
     for (i, chunk) in chunks.iter().enumerate() {
-        if working_relays.len() >= args.working_relay_num_goal {
+        if working_relays.len() >= working_relay_num_goal {
             break;
         }
 
@@ -215,28 +591,73 @@ async fn main() -> Result<()> {
         );
 
         let mut stream = stream::iter(chunk.iter().cloned())
-            .map(|r| tokio::spawn(check_relay(r, timeout_duration)))
-            .buffer_unordered(args.num_relays);
+            .map(|r| tokio::spawn(check_relay(r, timeout_duration, verify_tor, check_via_socks.clone())))
+            .buffer_unordered(num_relays);
 
         let mut found_in_attempt = false;
         while let Some(join_result) = stream.next().await {
             match join_result {
-                Ok((relay, reachable_addrs)) => {
+                Ok((relay, mut reachable_addrs)) => {
+                    if let Some((host, port, _)) = reachable_addrs.first().cloned() {
+                        if want_asn {
+                            let asn = match host.parse::<IpAddr>() {
+                                Ok(ip) => lookup_asn(ip, &asn_resolver, &asn_cache).await,
+                                Err(_) => None,
+                            };
+
+                            if let Some(k) = max_per_asn {
+                                if let Some(asn_num) = asn {
+                                    let count = asn_counts.entry(asn_num).or_insert(0);
+                                    if *count >= k {
+                                        println!(
+                                            "Skipping {} (AS{}): already have {} relay(s) from this network.",
+                                            format_address(&host, port),
+                                            asn_num,
+                                            count
+                                        );
+                                        reachable_addrs.clear();
+                                    } else {
+                                        *count += 1;
+                                    }
+                                }
+                            }
+
+                            if asn_report && !reachable_addrs.is_empty() {
+                                match asn {
+                                    Some(n) => println!("  {} -> AS{}", format_address(&host, port), n),
+                                    None => println!("  {} -> AS unknown", format_address(&host, port)),
+                                }
+                            }
+                        }
+                    }
+
                     if !reachable_addrs.is_empty() {
                         if !found_in_attempt {
                             println!("Reachable relays in this attempt:");
                             found_in_attempt = true;
                         }
-                        let mut out_str = String::new();
-                        for (host, port) in &reachable_addrs {
-                            let addr = format_address(host, *port);
-                            out_str.push_str(&format!("{}{} {}\n", bridge_prefix, addr, relay.fingerprint));
-                        }
-                        if let Some(path) = &args.outfile {
-                            let mut file = fs::OpenOptions::new().append(true).open(path)?;
-                            file.write_all(out_str.as_bytes())?;
+                        if buffered {
+                            for (host, port, rtt) in &reachable_addrs {
+                                println!("  {} ({}ms)", format_address(host, *port), rtt.as_millis());
+                            }
                         } else {
-                            print!("{}", out_str);
+                            let mut out_str = String::new();
+                            for (host, port, rtt) in &reachable_addrs {
+                                out_str.push_str(&format_result_line(
+                                    bridge_prefix,
+                                    torrc_fmt,
+                                    host,
+                                    *port,
+                                    &relay.fingerprint,
+                                    *rtt,
+                                ));
+                            }
+                            if let Some(path) = &outfile {
+                                let mut file = fs::OpenOptions::new().append(true).open(path)?;
+                                file.write_all(out_str.as_bytes())?;
+                            } else {
+                                print!("{}", out_str);
+                            }
                         }
                         working_relays.push((relay, reachable_addrs));
                     }
@@ -255,13 +676,51 @@ async fn main() -> Result<()> {
         println!("No working relays found.");
     } else {
         println!("Found {} working relays in total.", working_relays.len());
-        if let Some(path) = &args.outfile {
+
+        if buffered {
+            let mut results: Vec<(String, String, u16, Duration)> = working_relays
+                .iter()
+                .flat_map(|(relay, addrs)| {
+                    addrs
+                        .iter()
+                        .map(move |(host, port, rtt)| (relay.fingerprint.clone(), host.clone(), *port, *rtt))
+                })
+                .collect();
+            results.sort_by_key(|(_, _, _, rtt)| *rtt);
+            if let Some(top) = top {
+                let dropped = results.len().saturating_sub(top);
+                if dropped > 0 {
+                    println!("Keeping the {} lowest-latency of {} results (dropping {}).", top, results.len(), dropped);
+                }
+                results.truncate(top);
+            }
+
+            let mut out_str = String::new();
+            for (fingerprint, host, port, rtt) in &results {
+                out_str.push_str(&format_result_line(
+                    bridge_prefix,
+                    torrc_fmt,
+                    host,
+                    *port,
+                    fingerprint,
+                    *rtt,
+                ));
+            }
+            if let Some(path) = &outfile {
+                let mut file = fs::OpenOptions::new().append(true).open(path)?;
+                file.write_all(out_str.as_bytes())?;
+            } else {
+                print!("{}", out_str);
+            }
+        }
+
+        if let Some(path) = &outfile {
             println!("Results saved to {}", path.display());
-            if args.torrc_fmt {
+            if torrc_fmt {
                 let mut file = fs::OpenOptions::new().append(true).create(true).open(path)?;
                 file.write_all(b"UseBridges 1\n")?;
             }
-        } else if args.torrc_fmt {
+        } else if torrc_fmt {
             println!("Add the following line to your torrc file:\nUseBridges 1");
         }
     }
@@ -270,3 +729,89 @@ async fn main() -> Result<()> {
     //stdin().read_line(&mut String::new())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cymru_query_name_v4() {
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+        assert_eq!(cymru_query_name(ip), "8.8.8.8.origin.asn.cymru.com.");
+    }
+
+    #[test]
+    fn cymru_query_name_v6() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            cymru_query_name(ip),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.origin6.asn.cymru.com."
+        );
+    }
+
+    #[test]
+    fn parse_asn_txt_agreement() {
+        let records = ["15169 | 8.8.8.0/24 | US | arin | 2000-03-30"];
+        assert_eq!(parse_asn_txt(records.iter().copied()), Some(15169));
+    }
+
+    #[test]
+    fn parse_asn_txt_disagreement_is_unknown() {
+        let records = [
+            "15169 | 8.8.8.0/24 | US | arin | 2000-03-30",
+            "36492 | 8.8.8.0/24 | US | arin | 2000-03-30",
+        ];
+        assert_eq!(parse_asn_txt(records.iter().copied()), None);
+    }
+
+    #[test]
+    fn parse_asn_txt_empty_is_unknown() {
+        assert_eq!(parse_asn_txt(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn merge_settings_falls_back_to_defaults() {
+        let settings = merge_settings(&Args::default(), &ConfigFile::default());
+        assert_eq!(settings.num_relays, DEFAULT_NUM_RELAYS);
+        assert_eq!(
+            settings.working_relay_num_goal,
+            DEFAULT_WORKING_RELAY_NUM_GOAL
+        );
+        assert_eq!(settings.timeout_secs, DEFAULT_TIMEOUT);
+        assert!(!settings.verify_tor);
+        assert!(settings.urls.is_empty());
+    }
+
+    #[test]
+    fn merge_settings_config_fills_in_over_defaults() {
+        let config = ConfigFile {
+            num_relays: Some(50),
+            verify_tor: Some(true),
+            url: Some(vec!["https://example.com".to_string()]),
+            ..ConfigFile::default()
+        };
+        let settings = merge_settings(&Args::default(), &config);
+        assert_eq!(settings.num_relays, 50);
+        assert!(settings.verify_tor);
+        assert_eq!(settings.urls, vec!["https://example.com".to_string()]);
+    }
+
+    #[test]
+    fn merge_settings_cli_wins_over_config() {
+        let config = ConfigFile {
+            num_relays: Some(50),
+            url: Some(vec!["https://config.example".to_string()]),
+            ..ConfigFile::default()
+        };
+        let args = Args {
+            num_relays: Some(5),
+            url: vec!["https://cli.example".to_string()],
+            torrc_fmt: true,
+            ..Args::default()
+        };
+        let settings = merge_settings(&args, &config);
+        assert_eq!(settings.num_relays, 5);
+        assert_eq!(settings.urls, vec!["https://cli.example".to_string()]);
+        assert!(settings.torrc_fmt);
+    }
+}